@@ -1,12 +1,27 @@
 use clap::{Parser};
-use std::{thread::{self},time};
+use std::sync::{Arc,Mutex,mpsc};
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::thread;
+use std::collections::BTreeMap;
+use std::io::{Read,Write};
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration,Instant};
+
+// retry backoff starts here and doubles each attempt, up to this ceiling
+const BACKOFF_START:Duration = Duration::from_secs(1);
+const BACKOFF_MAX:Duration = Duration::from_secs(60);
+
+// size of the read-buffer used when streaming a throttled response body
+const CHUNK_SIZE:usize = 16*1024;
 
 #[derive(Parser, Debug)]
 #[clap(author="Kato", version, about="Download image tiles from a webserver. Replaces {x}, {y} in url with tile-number, {z} with zoom-level, and {bounds} with a bounding-box. Reprojection is not supported")]
 struct Args {
-	#[clap(short='u',long)]
-	/// Example: http://maps/{Z}/{X}/{Y}.png or https://map?bbox={bounds}
-	url: String,
+	#[clap(short='u',long,required=true,num_args=1..)]
+	/// Example: http://maps/{z}/{x}/{y}.png or https://map?bbox={bounds}.
+	/// May be given several times for mirrors (a.tile, b.tile, ...) - each tile
+	/// tries the next mirror on failure
+	url: Vec<String>,
 
 	#[clap(default_value=".",short='o',long)]
 	/// Tiles saved here in directories Z/X/Y
@@ -30,37 +45,420 @@ struct Args {
 
 	#[clap(default_value="10",long)]
 	/// Number of concurrent http-requests
-	concurrent_requests: usize
+	concurrent_requests: usize,
+
+	#[clap(default_value="5",long)]
+	/// Number of retries on transient errors (429/5xx/network) before giving up
+	max_retries: u32,
+
+	#[clap(long,value_parser=clap::value_parser!(u64).range(1..))]
+	/// Aggregate download rate-cap in bytes/sec across all workers. Unlimited if unset
+	speed_limit: Option<u64>,
+
+	#[clap(long)]
+	/// Restrict downloads to a geographic region: minlon,minlat,maxlon,maxlat
+	bbox: Option<String>,
+
+	#[clap(long,default_value=concat!("tile-dl/", env!("CARGO_PKG_VERSION")))]
+	/// User-Agent sent with every request
+	user_agent: String,
+
+	#[clap(short='H',long="header")]
+	/// Extra request header as "Name: Value". May be repeated
+	headers: Vec<String>,
+
+	#[clap(long)]
+	/// Skip tiles whose output file already exists and is non-empty
+	resume: bool
+}
+
+// a geographic region to download, in degrees
+struct BBox {
+	min_lon: f64,
+	min_lat: f64,
+	max_lon: f64,
+	max_lat: f64,
+}
+
+// result of a single tile-request, reported by a worker to the reporter thread.
+// (status) is the HTTP status code, or None when the request failed outright
+struct Stat {
+	start: Instant,
+	end: Instant,
+	status: Option<u16>,
+	bytes: u64,
+}
+
+// consume Stat records off (rx), print a live progress line as they arrive, and
+// print a final summary once every worker has dropped its sender. (in_flight)
+// is the count of tiles a worker has picked up but not yet reported
+fn report(rx:mpsc::Receiver<Stat>, in_flight:Arc<AtomicUsize>) {
+	let mut completed = 0usize;
+	let mut failed = 0usize;
+	let mut latencies:Vec<Duration> = Vec::new();
+	let mut total_bytes = 0u64;
+	let mut statuses:BTreeMap<String,usize> = BTreeMap::new();
+	let wall_start = Instant::now();
+
+	loop {
+		match rx.recv_timeout(Duration::from_millis(500)) {
+			Ok(stat) => {
+				completed += 1;
+				latencies.push(stat.end.duration_since(stat.start));
+				total_bytes += stat.bytes;
+				match stat.status {
+					// any observed code is bucketed; only a 2xx counts as a success
+					Some(code) => {
+						*statuses.entry(code.to_string()).or_insert(0) += 1;
+						if !(200..300).contains(&code) { failed += 1; }
+					}
+					// no response at all (network error) - bucket it separately
+					None => { failed += 1; *statuses.entry("error".to_string()).or_insert(0) += 1; }
+				}
+			}
+			// no record for a while - refresh the live line
+			Err(RecvTimeoutError::Timeout) => {}
+			// all workers are gone, the run is done
+			Err(RecvTimeoutError::Disconnected) => break,
+		}
+
+		// in-flight = tiles a worker has actually picked up and not yet reported
+		print!("\r{} done, {} failed, {} in-flight   ", completed, failed, in_flight.load(Ordering::Relaxed));
+		let _ = std::io::stdout().flush();
+	}
+	println!();
+
+	// final summary
+	let wall = wall_start.elapsed();
+	let succeeded = completed - failed;
+	println!("--- summary ---");
+	println!("total tiles:  {}", completed);
+	if completed > 0 {
+		println!("success rate: {:.1}%", 100.0 * succeeded as f64 / completed as f64);
+	}
+	if !latencies.is_empty() {
+		latencies.sort();
+		let percentile = |p:f64| -> Duration {
+			let idx = ((latencies.len() as f64 - 1.0) * p).round() as usize;
+			return latencies[idx];
+		};
+		println!("latency:      p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+			percentile(0.50), percentile(0.90), percentile(0.99), latencies[latencies.len()-1]);
+	}
+	if wall.as_secs_f64() > 0.0 {
+		println!("throughput:   {:.1} KiB/s ({} bytes in {:?})",
+			total_bytes as f64 / 1024.0 / wall.as_secs_f64(), total_bytes, wall);
+	}
+	println!("status codes:");
+	for (code, count) in &statuses {
+		println!("  {}: {}", code, count);
+	}
+}
+
+// Web Mercator (slippy-map) tile math. Tiles are numbered 0..2^z on each axis,
+// with x increasing eastward and y increasing southward from the top.
+fn lon_to_tile_x(lon:f64, n:f64) -> f64 {
+	return (lon + 180.0) / 360.0 * n;
+}
+
+fn lat_to_tile_y(lat:f64, n:f64) -> f64 {
+	let lat_rad = lat.to_radians();
+	return (1.0 - (lat_rad.tan() + 1.0/lat_rad.cos()).ln()/std::f64::consts::PI) / 2.0 * n;
+}
+
+// inverse of the above: the west/north edge of tile (x)/(y)
+fn tile_x_to_lon(x:f64, n:f64) -> f64 {
+	return x / n * 360.0 - 180.0;
+}
+
+fn tile_y_to_lat(y:f64, n:f64) -> f64 {
+	let m = std::f64::consts::PI * (1.0 - 2.0*y/n);
+	return m.sinh().atan().to_degrees();
+}
+
+// a single download-job: fetch one of (urls) (mirrors, tried in order) and save
+// it to (path)
+struct Job {
+	urls: Vec<String>,
+	path: String,
+}
+
+// shared download-budget that caps the aggregate throughput across all workers.
+// implemented as a token-bucket: tokens (bytes) refill at (bytes_per_sec) up to
+// a one-second cap, so idle time never banks more than a single second of burst
+// and the instantaneous aggregate rate stays bounded - unlike an all-time
+// average, which lets a quiet spell fund an arbitrarily large spike afterwards
+struct Throttle {
+	bytes_per_sec: u64,
+	capacity: f64,
+	tokens: f64,
+	last: Instant,
 }
 
-// run a http GET-request (url), save the response content to file (path)
-// return success or failure
-fn run_request(url:&str, path:&str) -> Result<(),Box<dyn std::error::Error>>
+impl Throttle {
+	fn new(bytes_per_sec:u64) -> Throttle {
+		// start full so the very first request isn't needlessly delayed
+		let capacity = bytes_per_sec as f64;
+		return Throttle{bytes_per_sec, capacity, tokens:capacity, last:Instant::now()};
+	}
+
+	// account for (bytes) just transferred and return how long the caller
+	// should sleep so the bucket never sustains more than (bytes_per_sec)
+	fn record(&mut self, bytes:u64) -> Duration {
+		// refill for the time elapsed since the last call, capped at one second
+		let now = Instant::now();
+		let elapsed = now.duration_since(self.last).as_secs_f64();
+		self.last = now;
+		self.tokens = (self.tokens + elapsed * self.bytes_per_sec as f64).min(self.capacity);
+
+		// spend the tokens; a shortfall must be waited out at the configured rate
+		self.tokens -= bytes as f64;
+		if self.tokens < 0.0 {
+			return Duration::from_secs_f64(-self.tokens / self.bytes_per_sec as f64);
+		}
+		return Duration::ZERO;
+	}
+}
+
+// try each mirror in (urls) in turn, returning the first success. transient
+// failures within a mirror are retried (see fetch_one); once a mirror is
+// exhausted the next one is tried, and the last error is surfaced if all fail
+fn run_request(client:&reqwest::blocking::Client, urls:&[String], path:&str, max_retries:u32, throttle:&Option<Arc<Mutex<Throttle>>>) -> Result<(u16,u64),(Option<u16>,Box<dyn std::error::Error>)>
 {
-	let client = reqwest::blocking::Client::builder().danger_accept_invalid_certs(true).build()?;
-	let mut res = client.get(url).send()?;
-	let mut file = std::fs::File::create(path)?;
-	let _n = res.copy_to(&mut file)?;
-	return Ok(());
+	let mut last_err:Option<(Option<u16>,Box<dyn std::error::Error>)> = None;
+	for url in urls {
+		match fetch_one(client, url, path, max_retries, throttle) {
+			Ok(result) => return Ok(result),
+			Err(err) => { last_err = Some(err); }
+		}
+	}
+	return Err(last_err.unwrap_or_else(|| (None, "no url given".into())));
+}
+
+// copy the whole response body into (file), returning the number of bytes
+// written. honours the shared rate-cap by streaming in fixed-size chunks when a
+// throttle is configured. a mid-body connection drop surfaces here as an error
+fn stream_body(res:&mut reqwest::blocking::Response, file:&mut std::fs::File, throttle:&Option<Arc<Mutex<Throttle>>>) -> Result<u64,Box<dyn std::error::Error>>
+{
+	match throttle {
+		// rate-limited: stream the body in fixed-size chunks and sleep
+		// whenever the shared budget says we are going too fast
+		Some(throttle) => {
+			let mut buf = [0u8; CHUNK_SIZE];
+			let mut total = 0u64;
+			loop {
+				let n = res.read(&mut buf)?;
+				if n == 0 { break; }
+				file.write_all(&buf[..n])?;
+				total += n as u64;
+				let wait = throttle.lock().unwrap().record(n as u64);
+				if !wait.is_zero() {
+					thread::sleep(wait);
+				}
+			}
+			return Ok(total);
+		}
+		// unthrottled: let reqwest copy the whole body in one go
+		None => {
+			return Ok(res.copy_to(file)?);
+		}
+	}
+}
+
+// run a http GET-request (url), save the response content to file (path).
+// transient failures (network errors, 429 and 5xx responses, and mid-body
+// connection drops) are retried with exponential backoff up to (max_retries)
+// extra attempts before giving up. on success it returns the final HTTP status
+// code and the body length in bytes; on failure it returns the last observed
+// status code (if any - None for pure network errors) alongside the error, so
+// the statistics subsystem can still account for the 429/4xx/5xx that was seen
+fn fetch_one(client:&reqwest::blocking::Client, url:&str, path:&str, max_retries:u32, throttle:&Option<Arc<Mutex<Throttle>>>) -> Result<(u16,u64),(Option<u16>,Box<dyn std::error::Error>)>
+{
+	let tmp = format!("{}.tmp", path);
+	let mut backoff = BACKOFF_START;
+	let mut attempt = 0;
+	loop {
+		attempt += 1;
+
+		// try to fetch - a network error is transient, so retry if attempts remain
+		let res = match client.get(url).send() {
+			Ok(res) => res,
+			Err(err) => {
+				if attempt > max_retries {
+					return Err((None, format!("{} (after {} attempts)", err, attempt).into()));
+				}
+				thread::sleep(backoff);
+				backoff = std::cmp::min(backoff*2, BACKOFF_MAX);
+				continue;
+			}
+		};
+
+		// retry on 429 (too-many-requests) and any 5xx server error
+		let status = res.status();
+		if status.as_u16() == 429 || status.is_server_error() {
+			if attempt > max_retries {
+				return Err((Some(status.as_u16()), format!("server returned {} (after {} attempts)", status, attempt).into()));
+			}
+			thread::sleep(backoff);
+			backoff = std::cmp::min(backoff*2, BACKOFF_MAX);
+			continue;
+		}
+
+		// capture the code before error_for_status consumes the response, so a
+		// permanent non-success (e.g. 404) is still surfaced to the statistics
+		let code = status.as_u16();
+		let mut res = match res.error_for_status() {
+			Ok(res) => res,
+			Err(err) => return Err((Some(code), err.into())),
+		};
+
+		// stream into a temporary sibling first, then atomically rename it into
+		// place only after a complete copy - a crash mid-download therefore never
+		// leaves a truncated tile that --resume would treat as finished
+		let mut file = match std::fs::File::create(&tmp) {
+			Ok(file) => file,
+			Err(err) => return Err((Some(code), err.into())),
+		};
+		match stream_body(&mut res, &mut file, throttle) {
+			Ok(bytes) => {
+				// flush to disk before swapping the temp file into its final name
+				if let Err(err) = file.sync_all() {
+					let _ = std::fs::remove_file(&tmp);
+					return Err((Some(code), err.into()));
+				}
+				drop(file);
+				if let Err(err) = std::fs::rename(&tmp, path) {
+					let _ = std::fs::remove_file(&tmp);
+					return Err((Some(code), err.into()));
+				}
+				return Ok((code, bytes));
+			}
+			// the connection dropped mid-body - drop the partial temp file and
+			// retry like any other transient network error
+			Err(err) => {
+				drop(file);
+				let _ = std::fs::remove_file(&tmp);
+				if attempt > max_retries {
+					return Err((None, format!("{} (after {} attempts)", err, attempt).into()));
+				}
+				thread::sleep(backoff);
+				backoff = std::cmp::min(backoff*2, BACKOFF_MAX);
+				continue;
+			}
+		}
+	}
 }
 
 fn main() {
-	
+
 	// parse command-line-arguments
 	let args = Args::parse();
 	//println!("{:?}", args);
 
-	// dynamic array to store running threads
-	let mut handles:Vec<thread::JoinHandle<()>> = Vec::new();
+	// build the shared http-client once, with the configured User-Agent and any
+	// extra headers. reqwest clients are cheap to clone (internally ref-counted)
+	let mut header_map = reqwest::header::HeaderMap::new();
+	for h in &args.headers {
+		let (name, value) = h.split_once(':').unwrap_or_else(|| panic!("--header expects \"Name: Value\", got {:?}", h));
+		let name = reqwest::header::HeaderName::from_bytes(name.trim().as_bytes()).expect("invalid header name");
+		let value = reqwest::header::HeaderValue::from_str(value.trim()).expect("invalid header value");
+		header_map.insert(name, value);
+	}
+	let client = reqwest::blocking::Client::builder()
+		.danger_accept_invalid_certs(true)
+		.user_agent(&args.user_agent)
+		.default_headers(header_map)
+		.build()
+		.unwrap();
+
+	// shared job-queue: the main thread is the producer, the workers are consumers.
+	// a bounded (sync) channel applies backpressure so the producer can only run a
+	// little ahead of the workers, capping the in-memory backlog regardless of how
+	// many tiles a zoom level contains
+	let (tx, rx) = mpsc::sync_channel::<Job>(args.concurrent_requests);
+	let rx = Arc::new(Mutex::new(rx));
+
+	// optional shared rate-cap handed to every worker
+	let throttle = args.speed_limit.map(|limit| Arc::new(Mutex::new(Throttle::new(limit))));
+
+	// stats channel: every worker reports one record per tile to the reporter.
+	// (in_flight) counts tiles a worker has pulled but not yet reported
+	let (stats_tx, stats_rx) = mpsc::channel::<Stat>();
+	let in_flight = Arc::new(AtomicUsize::new(0));
+	let reporter = {
+		let in_flight = Arc::clone(&in_flight);
+		thread::spawn(move|| report(stats_rx, in_flight))
+	};
+
+	// parse the optional geographic bounding-box
+	let bbox = args.bbox.as_ref().map(|s| {
+		let v:Vec<f64> = s.split(',').map(|p| p.trim().parse().expect("--bbox expects minlon,minlat,maxlon,maxlat")).collect();
+		if v.len() != 4 {
+			panic!("--bbox expects minlon,minlat,maxlon,maxlat");
+		}
+		BBox{min_lon:v[0], min_lat:v[1], max_lon:v[2], max_lat:v[3]}
+	});
+
+	// spawn a fixed pool of worker-threads that pull jobs off the queue
+	let mut workers:Vec<thread::JoinHandle<()>> = Vec::with_capacity(args.concurrent_requests);
+	for _ in 0..args.concurrent_requests {
+		let rx = Arc::clone(&rx);
+		let throttle = throttle.clone();
+		let stats_tx = stats_tx.clone();
+		let in_flight = Arc::clone(&in_flight);
+		let client = client.clone();
+		let max_retries = args.max_retries;
+		workers.push(thread::spawn(move||{
+			loop {
+				// lock the queue just long enough to take the next job
+				let job = rx.lock().unwrap().recv();
+				let job = match job {
+					Ok(job) => job,
+					// the sender has been dropped and the queue is empty - time to exit
+					Err(_) => break,
+				};
+				// this tile is now actively downloading
+				in_flight.fetch_add(1, Ordering::Relaxed);
+				let start = Instant::now();
+				let (status, bytes) = match run_request(&client, &job.urls, job.path.as_str(), max_retries, &throttle) {
+					Ok((code, bytes)) => (Some(code), bytes),
+					// keep the observed status code (if any) so the histogram
+					// still reflects the 429/4xx/5xx that caused the failure
+					Err((code, err)) => {
+						println!("Failed to save {}. Error: {}", job.path, err);
+						(code, 0)
+					}
+				};
+				let _ = stats_tx.send(Stat{start, end:Instant::now(), status, bytes});
+				in_flight.fetch_sub(1, Ordering::Relaxed);
+			}
+		}));
+	}
 
 	// loop through zoom-levels
 	for z in args.start_zoom..args.end_zoom+1 {
-		
+
 		// compute nr of tiles in this zoomlevel
 		let n = u32::pow(2,z);
+		let nf = n as f64;
+
+		// work out which tiles this zoom-level needs. without a bbox we cover the
+		// whole grid (from the --x/--y origin); with one we clamp to the tiles that
+		// overlap the requested region. note y grows southward, so max_lat maps to
+		// the smallest y and min_lat to the largest
+		let (x_start, x_end, y_start, y_end) = match &bbox {
+			Some(b) => {
+				let x_start = (lon_to_tile_x(b.min_lon, nf).floor() as i64).clamp(0, n as i64 - 1) as u32;
+				let x_end   = (lon_to_tile_x(b.max_lon, nf).floor() as i64).clamp(0, n as i64 - 1) as u32;
+				let y_start = (lat_to_tile_y(b.max_lat, nf).floor() as i64).clamp(0, n as i64 - 1) as u32;
+				let y_end   = (lat_to_tile_y(b.min_lat, nf).floor() as i64).clamp(0, n as i64 - 1) as u32;
+				(x_start, x_end+1, y_start, y_end+1)
+			}
+			None => (args.x, n, args.y, n),
+		};
 
 		// iterate through x-tiles
-		for x in args.x..n {
+		for x in x_start..x_end {
 
 			// make sure the directory z/x/ exists
 			let directory = format!("{}/{}/{}", args.output_dir, z.to_string(), x.to_string());
@@ -69,61 +467,54 @@ fn main() {
 			}
 
 			// iterate through y-tiles
-			for y in args.y..n {
-
-				// inject x,y,z values into url
-				let mut url = args.url
-				.replace("{x}", x.to_string().as_str())
-				.replace("{y}", y.to_string().as_str())
-				.replace("{z}", z.to_string().as_str());
-
-				// if applicable, inject bounds values
-				if url.contains("{bounds}"){
-					let lon_step = 360.0 / n as f32;
-					let lon = (x as f32 * lon_step) - 180.0;
-					let lat_step = 180.0 / n as f32;
-					let lat = -(y as f32 * lat_step) + 90.0;
-					url = url.replace("{bounds}", format!("({},{},{},{})", lat, lat-lat_step, lon, lon+lon_step).as_str());
-				}
+			for y in y_start..y_end {
 
-				// if there is more running threads - than arg.concurrent_threads
-				while handles.len() > args.concurrent_requests {
-					// take a little break, - waiting for threads to finish..
-					thread::sleep(time::Duration::from_millis(10));
-
-					// loop through the threads, and check if anyone has finished
-					for i in 0..handles.len() {
-						if let Some(h) = handles.get(i) {
-							
-							// thread is finished, remove it from the list
-							if h.is_finished() {
-								handles.remove(i);
-							}
-						}
-					}
-				}
-				
-				// spawn a new thread, - move the path and url variable into it
+				// this tile's true Web Mercator extents, computed once and shared by
+				// every mirror. emitted in WMS bbox order - minlon,minlat,maxlon,maxlat
+				let bounds = {
+					let west = tile_x_to_lon(x as f64, nf);
+					let east = tile_x_to_lon(x as f64 + 1.0, nf);
+					let north = tile_y_to_lat(y as f64, nf);
+					let south = tile_y_to_lat(y as f64 + 1.0, nf);
+					format!("{},{},{},{}", west, south, east, north)
+				};
+
+				// inject x,y,z (and bounds, if present) into each mirror url
+				let urls:Vec<String> = args.url.iter().map(|u| {
+					u.replace("{x}", x.to_string().as_str())
+					.replace("{y}", y.to_string().as_str())
+					.replace("{z}", z.to_string().as_str())
+					.replace("{bounds}", bounds.as_str())
+				}).collect();
+
+				// push the job onto the queue - a free worker will pick it up
 				let path = format!("{}/{}.png", directory, y.to_string());
-				handles.push(thread::spawn(move||{
 
-					if let Err(err) = run_request(url.as_str(), path.as_str()) {
-						println!("Failed to save {}. Error: {}", url, err);
+				// in resume-mode, skip tiles that already downloaded successfully
+				// (the file exists and is non-empty - partial writes never reach
+				// this name thanks to the atomic tmp-rename in fetch_one)
+				if args.resume {
+					if let Ok(meta) = std::fs::metadata(&path) {
+						if meta.len() > 0 { continue; }
 					}
-				}));
+				}
+
+				tx.send(Job{urls, path}).unwrap();
 			}
 		}
 	}
 
-	// wait until all threads are done
-	while !handles.is_empty() {
-		thread::sleep(time::Duration::from_millis(100));
-		for i in 0..handles.len() {
-			if let Some(h) = handles.get(i) {
-				if h.is_finished() {
-					handles.remove(i);
-				}
-			}
-		}
+	// drop the producer's sender so workers see the queue close once it drains.
+	// also drop our own stats-sender so only the workers' clones keep it alive
+	drop(tx);
+	drop(stats_tx);
+
+	// wait until all workers have exited
+	for worker in workers {
+		worker.join().unwrap();
 	}
+
+	// workers are gone, so their stats-senders are dropped - let the reporter
+	// print its final summary and finish
+	reporter.join().unwrap();
 }